@@ -0,0 +1,370 @@
+// Copyright 2022 Zinc Labs Inc. and Contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tamper-evident manifests for WAL series files.
+//!
+//! Every time [`super::write_series_file`] rotates a WAL segment, it emits a
+//! [`SeriesManifest`] describing the files it just wrote: their full name,
+//! byte length and a BLAKE3 digest of their contents, alongside the
+//! ingestion timestamp and org/stream identity. The manifest is signed with
+//! a configured private key so that the compaction and query paths can
+//! verify a file's digest against the signed manifest before trusting its
+//! contents, and so operators can detect silent corruption or post-hoc
+//! edits of on-disk metric segments.
+//!
+//! Trust in the signing key itself is rooted in a small [`RootOfTrust`]
+//! document (key id, public key, expiry), in the same spirit as a TUF
+//! signed metadata root: readers verify a manifest's signature against the
+//! root before verifying the files it describes.
+
+use ahash::AHashMap;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::common;
+use crate::common::infra::config::CONFIG;
+
+/// Stream name manifests are written under, analogous to
+/// [`super::super::prom::SERIES_NAME`] for the series stream itself.
+pub const MANIFEST_NAME: &str = "metrics_manifest";
+
+/// Digest/length accumulated so far for a WAL file that is still open for
+/// writes, i.e. hasn't rotated out yet.
+struct PendingFile {
+    hour_key: String,
+    hasher: blake3::Hasher,
+    byte_len: u64,
+}
+
+/// The file each `(org_id, thread_id)` WAL writer is currently accumulating
+/// a digest for, keyed by `"{org_id}:{thread_id}"`. WAL writers are
+/// per-thread (see `get_or_create`'s `thread_id` parameter), so two threads
+/// serving the same org hold two unrelated `file_name`s at once; keying on
+/// `org_id` alone would make one thread's writes look like a rotation of
+/// the other thread's file and finalize it mid-write.
+static ACTIVE_FILE: Lazy<Mutex<AHashMap<String, String>>> = Lazy::new(|| Mutex::new(AHashMap::new()));
+
+/// Accumulated digest state, keyed by `"{org_id}:{thread_id}:{file_name}"`.
+static PENDING: Lazy<Mutex<AHashMap<String, PendingFile>>> = Lazy::new(|| Mutex::new(AHashMap::new()));
+
+/// A WAL series file that has finished accumulating writes because a
+/// different file has started for the same org, i.e. it rotated out and is
+/// now final. Carries everything needed to sign a [`SeriesManifest`] that
+/// describes the *complete* file, not just the latest call's increment.
+pub struct RotatedFile {
+    pub file_name: String,
+    pub hour_key: String,
+    pub byte_len: u64,
+    /// Hex-encoded BLAKE3 digest over every byte ever written to the file.
+    pub digest: String,
+}
+
+/// Records `bytes` as newly appended to `file_name` (the WAL file returned
+/// by `get_or_create` for the current call, on WAL writer thread
+/// `thread_id`), accumulating the running BLAKE3 digest and byte count
+/// across calls so they describe the file's full contents, not just one
+/// call's increment.
+///
+/// `write_series_file` may be called many times against the same
+/// underlying file before the WAL rotates it out, so the digest can only be
+/// finalized once a *different* file starts on this same `(org_id,
+/// thread_id)` writer: at that point the previously-accumulating file is
+/// done and this returns its final state as a [`RotatedFile`] for the
+/// caller to sign and persist. Returns `None` while the file named
+/// `file_name` is still the one actively accumulating.
+pub fn record_write(
+    org_id: &str,
+    thread_id: usize,
+    file_name: &str,
+    hour_key: &str,
+    bytes: &[u8],
+) -> Option<RotatedFile> {
+    let writer_key = format!("{org_id}:{thread_id}");
+    let rotated = {
+        let mut active = ACTIVE_FILE.lock();
+        match active.insert(writer_key.clone(), file_name.to_string()) {
+            Some(previous_name) if previous_name != file_name => {
+                let key = format!("{writer_key}:{previous_name}");
+                PENDING.lock().remove(&key).map(|pending| RotatedFile {
+                    file_name: previous_name,
+                    hour_key: pending.hour_key,
+                    byte_len: pending.byte_len,
+                    digest: hex::encode(pending.hasher.finalize().as_bytes()),
+                })
+            }
+            _ => None,
+        }
+    };
+
+    let key = format!("{writer_key}:{file_name}");
+    let mut pending = PENDING.lock();
+    let entry = pending.entry(key).or_insert_with(|| PendingFile {
+        hour_key: hour_key.to_string(),
+        hasher: blake3::Hasher::new(),
+        byte_len: 0,
+    });
+    entry.hasher.update(bytes);
+    entry.byte_len += bytes.len() as u64;
+
+    rotated
+}
+
+/// Loads the configured manifest signing key, if operators have set one.
+/// Returns the key id and parsed [`SigningKey`], or `None` if manifest
+/// signing is disabled (the default).
+pub fn configured_signing_key() -> Option<(String, SigningKey)> {
+    let key_id = CONFIG.common.metrics_manifest_key_id.clone();
+    let key_hex = &CONFIG.common.metrics_manifest_signing_key;
+    if key_id.is_empty() || key_hex.is_empty() {
+        return None;
+    }
+    let seed: [u8; 32] = hex::decode(key_hex).ok()?.try_into().ok()?;
+    Some((key_id, SigningKey::from_bytes(&seed)))
+}
+
+/// One series file recorded in a [`SeriesManifest`].
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SeriesFileEntry {
+    pub file_name: String,
+    pub byte_len: u64,
+    /// Hex-encoded BLAKE3 digest of the file's contents.
+    pub digest: String,
+}
+
+/// Signed record of the series files written during one WAL rotation.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SeriesManifest {
+    pub org_id: String,
+    pub stream_name: String,
+    pub ingested_at: i64,
+    pub files: Vec<SeriesFileEntry>,
+    /// Id of the root-of-trust key that produced [`Self::signature`].
+    pub key_id: String,
+    /// Hex-encoded ed25519 signature over the manifest's canonical JSON
+    /// encoding with `signature` itself cleared.
+    pub signature: String,
+}
+
+/// Root-of-trust document: the public key operators configure readers with,
+/// plus its id and expiry. Readers refuse to trust a manifest signed by a
+/// key id that isn't (yet) this root, or that has expired.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RootOfTrust {
+    pub key_id: String,
+    /// Hex-encoded ed25519 public key.
+    pub public_key: String,
+    pub expires_at: i64,
+}
+
+/// Computes the hex-encoded BLAKE3 digest of a file's contents, reusing the
+/// same hasher [`super::signature_without_labels`] uses for series hashing.
+pub fn digest_file(contents: &[u8]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(contents);
+    hex::encode(hasher.finalize().as_bytes())
+}
+
+fn canonical_bytes(manifest: &SeriesManifest) -> Vec<u8> {
+    let mut unsigned = manifest.clone();
+    unsigned.signature.clear();
+    common::json::to_vec(&unsigned).expect("SeriesManifest is always serializable")
+}
+
+/// Builds and signs a manifest for the given set of series files.
+pub fn sign_manifest(
+    org_id: &str,
+    stream_name: &str,
+    ingested_at: i64,
+    files: Vec<SeriesFileEntry>,
+    key_id: &str,
+    signing_key: &SigningKey,
+) -> SeriesManifest {
+    let mut manifest = SeriesManifest {
+        org_id: org_id.to_string(),
+        stream_name: stream_name.to_string(),
+        ingested_at,
+        files,
+        key_id: key_id.to_string(),
+        signature: String::new(),
+    };
+    let signature: Signature = signing_key.sign(&canonical_bytes(&manifest));
+    manifest.signature = hex::encode(signature.to_bytes());
+    manifest
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ManifestVerifyError {
+    #[error("manifest key id {0} does not match root of trust {1}")]
+    KeyIdMismatch(String, String),
+    #[error("root of trust key {0} expired at {1}")]
+    RootExpired(String, i64),
+    #[error("manifest signature does not verify against the root of trust's public key")]
+    BadSignature,
+    #[error("file {0} is not listed in the manifest")]
+    FileNotInManifest(String),
+    #[error("file {0} digest mismatch: manifest says {1}, contents hash to {2}")]
+    DigestMismatch(String, String, String),
+}
+
+/// Verifies a manifest's signature against a root of trust. Does not check
+/// any individual file's digest; use [`verify_file_against_manifest`] for
+/// that once the manifest itself is trusted.
+pub fn verify_manifest(
+    manifest: &SeriesManifest,
+    root: &RootOfTrust,
+    now: i64,
+) -> Result<(), ManifestVerifyError> {
+    if manifest.key_id != root.key_id {
+        return Err(ManifestVerifyError::KeyIdMismatch(
+            manifest.key_id.clone(),
+            root.key_id.clone(),
+        ));
+    }
+    if now >= root.expires_at {
+        return Err(ManifestVerifyError::RootExpired(
+            root.key_id.clone(),
+            root.expires_at,
+        ));
+    }
+    let public_key_bytes: [u8; 32] = hex::decode(&root.public_key)
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or(ManifestVerifyError::BadSignature)?;
+    let verifying_key =
+        VerifyingKey::from_bytes(&public_key_bytes).map_err(|_| ManifestVerifyError::BadSignature)?;
+    let signature_bytes: [u8; 64] = hex::decode(&manifest.signature)
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or(ManifestVerifyError::BadSignature)?;
+    let signature = Signature::from_bytes(&signature_bytes);
+    verifying_key
+        .verify(&canonical_bytes(manifest), &signature)
+        .map_err(|_| ManifestVerifyError::BadSignature)
+}
+
+/// Verifies that `contents` matches the digest recorded for `file_name` in
+/// an already-signature-verified manifest. Called by the compaction/query
+/// path before loading a WAL series file off disk.
+pub fn verify_file_against_manifest(
+    manifest: &SeriesManifest,
+    file_name: &str,
+    contents: &[u8],
+) -> Result<(), ManifestVerifyError> {
+    let entry = manifest
+        .files
+        .iter()
+        .find(|f| f.file_name == file_name)
+        .ok_or_else(|| ManifestVerifyError::FileNotInManifest(file_name.to_string()))?;
+    let actual = digest_file(contents);
+    if actual != entry.digest {
+        return Err(ManifestVerifyError::DigestMismatch(
+            file_name.to_string(),
+            entry.digest.clone(),
+            actual,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root_and_key() -> (RootOfTrust, SigningKey) {
+        let signing_key = SigningKey::from_bytes(&[9u8; 32]);
+        let root = RootOfTrust {
+            key_id: "key-1".to_string(),
+            public_key: hex::encode(signing_key.verifying_key().to_bytes()),
+            expires_at: 2_000_000_000,
+        };
+        (root, signing_key)
+    }
+
+    #[test]
+    fn sign_and_verify_round_trip() {
+        let (root, signing_key) = root_and_key();
+        let entry = SeriesFileEntry {
+            file_name: "series_123.wal".to_string(),
+            byte_len: 42,
+            digest: digest_file(b"hello world"),
+        };
+        let manifest = sign_manifest("org1", "series", 1000, vec![entry], &root.key_id, &signing_key);
+
+        verify_manifest(&manifest, &root, 0).expect("signature must verify against its own root");
+        verify_file_against_manifest(&manifest, "series_123.wal", b"hello world")
+            .expect("digest matches the contents it was computed from");
+    }
+
+    #[test]
+    fn verify_manifest_rejects_wrong_root() {
+        let (root, signing_key) = root_and_key();
+        let entry = SeriesFileEntry {
+            file_name: "series_123.wal".to_string(),
+            byte_len: 0,
+            digest: digest_file(b""),
+        };
+        let manifest = sign_manifest("org1", "series", 1000, vec![entry], &root.key_id, &signing_key);
+
+        let (mut wrong_root, _) = root_and_key();
+        wrong_root.key_id = "key-2".to_string();
+        let err = verify_manifest(&manifest, &wrong_root, 0).unwrap_err();
+        assert!(matches!(err, ManifestVerifyError::KeyIdMismatch(_, _)));
+    }
+
+    #[test]
+    fn verify_file_against_manifest_rejects_tampered_contents() {
+        let (root, signing_key) = root_and_key();
+        let entry = SeriesFileEntry {
+            file_name: "series_123.wal".to_string(),
+            byte_len: 11,
+            digest: digest_file(b"hello world"),
+        };
+        let manifest = sign_manifest("org1", "series", 1000, vec![entry], &root.key_id, &signing_key);
+
+        let err =
+            verify_file_against_manifest(&manifest, "series_123.wal", b"tampered!!!").unwrap_err();
+        assert!(matches!(err, ManifestVerifyError::DigestMismatch(_, _, _)));
+    }
+
+    #[test]
+    fn record_write_only_rotates_once_a_different_file_starts() {
+        let org = "org-record-write";
+        assert!(record_write(org, 0, "file-a", "2024/01/01/00", b"hello ").is_none());
+        assert!(record_write(org, 0, "file-a", "2024/01/01/00", b"world").is_none());
+
+        let rotated = record_write(org, 0, "file-b", "2024/01/01/01", b"next file")
+            .expect("starting file-b must finalize file-a");
+        assert_eq!(rotated.file_name, "file-a");
+        assert_eq!(rotated.byte_len, 11);
+        assert_eq!(rotated.digest, digest_file(b"hello world"));
+    }
+
+    #[test]
+    fn record_write_keeps_concurrent_threads_independent() {
+        let org = "org-threaded";
+        // Two WAL writer threads for the same org interleave calls against
+        // their own distinct files; neither should ever look like a
+        // rotation of the other's file.
+        assert!(record_write(org, 1, "thread-1-file", "2024/01/01/00", b"aaa").is_none());
+        assert!(record_write(org, 2, "thread-2-file", "2024/01/01/00", b"bbb").is_none());
+        assert!(record_write(org, 1, "thread-1-file", "2024/01/01/00", b"ccc").is_none());
+        assert!(record_write(org, 2, "thread-2-file", "2024/01/01/00", b"ddd").is_none());
+
+        let rotated = record_write(org, 1, "thread-1-file-2", "2024/01/01/01", b"eee")
+            .expect("thread 1 rotating to a new file finalizes only its own prior file");
+        assert_eq!(rotated.file_name, "thread-1-file");
+        assert_eq!(rotated.digest, digest_file(b"aaaccc"));
+    }
+}
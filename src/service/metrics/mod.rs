@@ -27,7 +27,9 @@ use crate::common::meta::{
 };
 
 pub mod json;
+pub mod manifest;
 pub mod prom;
+pub mod series_index;
 
 pub fn get_prom_metadata_from_schema(schema: &Schema) -> Option<Metadata> {
     let metadata = schema.metadata.get(METADATA_LABEL)?;
@@ -65,11 +67,38 @@ pub fn signature_without_labels(
     Signature(hasher.finalize().into())
 }
 
+/// Writes series rows out to the WAL.
+///
+/// `buf` is expected to already be decoded plaintext, one row per line. For
+/// the streaming SigV4 ingestion path, use
+/// [`write_series_file_from_aws_chunked`] instead, which verifies and
+/// unwraps `aws-chunked` framing before delegating here.
+///
+/// If a manifest signing key is configured (see
+/// [`manifest::configured_signing_key`]), this also accumulates a running
+/// BLAKE3 digest of the WAL file across however many calls land in the same
+/// rotation window (`get_or_create` returns the same file handle until the
+/// hour rolls over), and signs a [`manifest::SeriesManifest`] for the
+/// *previous* file only once it has actually rotated out, so the manifest
+/// describes that file's complete contents rather than one call's
+/// increment. See [`manifest::record_write`].
+///
+/// `series_meta` carries the `(signature, metric name, labels)` of every
+/// series being written so they can be upserted into the org's
+/// [`series_index::SeriesIndex`], keeping the hash-to-labels reverse index
+/// current without a separate backfill pass. A mismatch between a
+/// signature and its labels (a hash collision) is logged and otherwise
+/// skipped rather than failing the whole write. `label_exclude_names` MUST
+/// be the same exclude list the caller used to compute each signature in
+/// `series_meta` via [`signature_without_labels`] — passing a different one
+/// here doesn't detect real collisions, it just makes every upsert fail.
 pub fn write_series_file(
     buf: AHashMap<String, Vec<String>>,
     thread_id: usize,
     org_id: &str,
     stream_file_name: &mut String,
+    series_meta: &[(Signature, String, common::json::Map<String, common::json::Value>)],
+    label_exclude_names: &[&str],
 ) {
     let timestamp = Utc::now().timestamp_micros();
     let local_val: common::json::Map<String, common::json::Value> = common::json::Map::new();
@@ -89,9 +118,12 @@ pub fn write_series_file(
         &hour_key,
         CONFIG.common.wal_memory_mode_enabled,
     );
+    let file_name = file.full_name();
     if stream_file_name.is_empty() {
-        *stream_file_name = file.full_name();
+        *stream_file_name = file_name.clone();
     }
+    let signing_key = manifest::configured_signing_key();
+    let mut rotated_out = None;
     for (_, entry) in buf {
         if entry.is_empty() {
             continue;
@@ -102,9 +134,99 @@ pub fn write_series_file(
             write_buf.put("\n".as_bytes());
         }
         file.write(write_buf.as_ref());
+        if signing_key.is_some() {
+            if let Some(rotated) =
+                manifest::record_write(org_id, thread_id, &file_name, &hour_key, write_buf.as_ref())
+            {
+                rotated_out = Some(rotated);
+            }
+        }
+    }
+    if let (Some(rotated), Some((key_id, signing_key))) = (rotated_out, signing_key) {
+        let entry = manifest::SeriesFileEntry {
+            file_name: rotated.file_name,
+            byte_len: rotated.byte_len,
+            digest: rotated.digest,
+        };
+        let signed = manifest::sign_manifest(
+            org_id,
+            SERIES_NAME,
+            timestamp,
+            vec![entry],
+            &key_id,
+            &signing_key,
+        );
+        let manifest_bytes =
+            common::json::to_vec(&signed).expect("SeriesManifest is always serializable");
+        let manifest_file = get_or_create(
+            thread_id,
+            org_id,
+            manifest::MANIFEST_NAME,
+            StreamType::Metrics,
+            &rotated.hour_key,
+            CONFIG.common.wal_memory_mode_enabled,
+        );
+        manifest_file.write(&manifest_bytes);
+        manifest_file.write(b"\n");
+    }
+    if !series_meta.is_empty() {
+        let index = series_index::get_or_create_index(org_id);
+        for (signature, metric_name, labels) in series_meta {
+            if let Err(err) = index.upsert(
+                signature,
+                metric_name,
+                labels.clone(),
+                timestamp,
+                label_exclude_names,
+            ) {
+                log::warn!("series index upsert failed for {org_id}/{metric_name}: {err}");
+            }
+        }
     }
 }
 
+/// Entry point for the streaming SigV4 ingestion path: verifies and unwraps
+/// an `aws-chunked` / `STREAMING-AWS4-HMAC-SHA256-PAYLOAD` request body with
+/// [`crate::service::aws_chunked_sigv4::ChunkedSigV4Decoder`], splits the
+/// decoded payload into newline-delimited rows, and writes them via
+/// [`write_series_file`].
+///
+/// `ctx` is built by the caller from the request's `Authorization` and
+/// `x-amz-content-sha256` headers; `body` is the full request body as
+/// received. `series_meta` and `label_exclude_names` are forwarded
+/// unchanged to `write_series_file`.
+pub fn write_series_file_from_aws_chunked(
+    ctx: crate::service::aws_chunked_sigv4::SigningContext,
+    body: &[u8],
+    thread_id: usize,
+    org_id: &str,
+    stream_file_name: &mut String,
+    series_meta: &[(Signature, String, common::json::Map<String, common::json::Value>)],
+    label_exclude_names: &[&str],
+) -> Result<(), crate::service::aws_chunked_sigv4::ChunkedSigV4Error> {
+    let mut decoder = crate::service::aws_chunked_sigv4::ChunkedSigV4Decoder::new(ctx);
+    let mut rows = Vec::new();
+    for chunk in decoder.push(body)? {
+        rows.extend(
+            chunk
+                .split(|&b| b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| String::from_utf8_lossy(line).into_owned()),
+        );
+    }
+    let mut buf = AHashMap::new();
+    buf.insert(String::new(), rows);
+    write_series_file(
+        buf,
+        thread_id,
+        org_id,
+        stream_file_name,
+        series_meta,
+        label_exclude_names,
+    );
+    Ok(())
+}
+
 /// The schema of the `value` table is fixed.
 #[inline]
 pub fn get_value_schema() -> Arc<Schema> {
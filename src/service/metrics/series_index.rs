@@ -0,0 +1,194 @@
+// Copyright 2022 Zinc Labs Inc. and Contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reverse index from a series [`Signature`] back to its full label set.
+//!
+//! `get_value_schema` only stores `__name__`, `__hash__`, `_timestamp` and
+//! `value`, and [`signature_without_labels`] collapses labels down to an
+//! opaque 32-byte hash, so resolving a hash back to its labels at query time
+//! would otherwise mean scanning the separate series stream. This module
+//! maintains that mapping as series are written, so the query layer can
+//! answer label-enrichment and `series` API requests by lookup instead, and
+//! so hash collisions can be detected by re-hashing a resolved label set and
+//! checking it matches the [`Signature`] it was looked up by.
+
+use ahash::AHashMap;
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+use super::Signature;
+use crate::common;
+
+/// A resolved series: its metric name, full label set, and the time range
+/// it has been observed over.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SeriesEntry {
+    pub metric_name: String,
+    pub labels: common::json::Map<String, common::json::Value>,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SeriesIndexError {
+    #[error("hash collision: resolved label set for {0} re-hashes to a different signature")]
+    HashCollision(String),
+}
+
+/// In-memory reverse index, keyed by hex-encoded [`Signature`].
+///
+/// One instance is shared per org via [`SERIES_INDEXES`]; `write_series_file`
+/// upserts into it as it persists series so lookups stay current without a
+/// separate backfill pass.
+#[derive(Debug, Default)]
+pub struct SeriesIndex {
+    entries: RwLock<AHashMap<String, SeriesEntry>>,
+}
+
+impl SeriesIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts or updates the entry for `signature`, widening `first_seen`/
+    /// `last_seen` to cover `timestamp`. Verifies that `labels` actually
+    /// re-hashes to `signature`, returning [`SeriesIndexError::HashCollision`]
+    /// if not.
+    ///
+    /// `label_exclude_names` MUST be the exact same exclude list the caller
+    /// passed to [`super::signature_without_labels`] when it first computed
+    /// `signature`; a mismatch here doesn't indicate a real hash collision,
+    /// it just means the re-hash was computed differently than the original.
+    pub fn upsert(
+        &self,
+        signature: &Signature,
+        metric_name: &str,
+        labels: common::json::Map<String, common::json::Value>,
+        timestamp: i64,
+        label_exclude_names: &[&str],
+    ) -> Result<(), SeriesIndexError> {
+        let rehashed = super::signature_without_labels(&labels, label_exclude_names);
+        if &rehashed != signature {
+            return Err(SeriesIndexError::HashCollision(metric_name.to_string()));
+        }
+        let key: String = signature.clone().into();
+        let mut entries = self.entries.write();
+        entries
+            .entry(key)
+            .and_modify(|existing| {
+                existing.first_seen = existing.first_seen.min(timestamp);
+                existing.last_seen = existing.last_seen.max(timestamp);
+            })
+            .or_insert(SeriesEntry {
+                metric_name: metric_name.to_string(),
+                labels,
+                first_seen: timestamp,
+                last_seen: timestamp,
+            });
+        Ok(())
+    }
+
+    /// Resolves a hex-encoded `__hash__` value back to its label set.
+    pub fn get(&self, hash: &str) -> Option<SeriesEntry> {
+        self.entries.read().get(hash).cloned()
+    }
+
+    /// Returns every series whose metric name starts with `prefix`.
+    pub fn series_with_name_prefix(&self, prefix: &str) -> Vec<SeriesEntry> {
+        self.entries
+            .read()
+            .values()
+            .filter(|entry| entry.metric_name.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns every series with a label `name = value`, for label-matcher
+    /// style lookups from the query layer.
+    pub fn series_with_label(&self, name: &str, value: &str) -> Vec<SeriesEntry> {
+        self.entries
+            .read()
+            .values()
+            .filter(|entry| {
+                entry
+                    .labels
+                    .get(name)
+                    .and_then(|v| v.as_str())
+                    .map(|v| v == value)
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+static SERIES_INDEXES: once_cell::sync::Lazy<RwLock<AHashMap<String, Arc<SeriesIndex>>>> =
+    once_cell::sync::Lazy::new(|| RwLock::new(AHashMap::new()));
+
+/// Returns the shared [`SeriesIndex`] for `org_id`, creating it on first use.
+pub fn get_or_create_index(org_id: &str) -> Arc<SeriesIndex> {
+    if let Some(index) = SERIES_INDEXES.read().get(org_id) {
+        return index.clone();
+    }
+    let mut indexes = SERIES_INDEXES.write();
+    indexes
+        .entry(org_id.to_string())
+        .or_insert_with(|| Arc::new(SeriesIndex::new()))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels() -> common::json::Map<String, common::json::Value> {
+        let mut labels = common::json::Map::new();
+        labels.insert("__name__".to_string(), "http_requests_total".into());
+        labels.insert("method".to_string(), "GET".into());
+        labels.insert("status".to_string(), "200".into());
+        labels
+    }
+
+    /// Mirrors `write_series_file`'s actual call pattern: the `Signature` is
+    /// computed once at ingestion with a given exclude list, and `upsert`
+    /// must be given that *same* exclude list to re-verify it, not an
+    /// assumed one.
+    #[test]
+    fn upsert_succeeds_when_exclude_list_matches_original_signature() {
+        let labels = labels();
+        let exclude = ["__name__"];
+        let signature = super::super::signature_without_labels(&labels, &exclude);
+
+        let index = SeriesIndex::new();
+        index
+            .upsert(&signature, "http_requests_total", labels.clone(), 1000, &exclude)
+            .expect("exclude list matches, so the re-hash must match too");
+
+        let key: String = signature.into();
+        let resolved = index.get(&key).expect("entry should be present");
+        assert_eq!(resolved.labels, labels);
+    }
+
+    #[test]
+    fn upsert_rejects_an_exclude_list_that_does_not_match_the_original() {
+        let labels = labels();
+        let signature = super::super::signature_without_labels(&labels, &["__name__"]);
+
+        let index = SeriesIndex::new();
+        let err = index
+            .upsert(&signature, "http_requests_total", labels, 1000, &[])
+            .unwrap_err();
+        assert!(matches!(err, SeriesIndexError::HashCollision(_)));
+    }
+}